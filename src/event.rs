@@ -2,7 +2,12 @@
 //! and handle top-level state, as well as handle input events such as keyboard
 //! and mouse.
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error::Error as StdError;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
 
 /// A key code.
 pub use sdl2::keyboard::Keycode;
@@ -19,6 +24,17 @@ pub use sdl2::controller::Button;
 /// A controller axis.
 pub use sdl2::controller::Axis;
 
+/// The stage of a touch gesture: a finger touching down, moving while
+/// still down, lifting off, or the gesture being cancelled (e.g. by the
+/// OS bringing up a system gesture).
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TouchPhase {
+    Started,
+    Moved,
+    Ended,
+    Cancelled,
+}
+
 use sdl2::event::Event::*;
 use sdl2::event;
 use sdl2::mouse;
@@ -32,6 +48,132 @@ use timer;
 
 use std::time::Duration;
 
+/// Tracks the current keyboard and mouse state so it can be polled from
+/// `update()` instead of only reacted to through event callbacks. The
+/// `run()` loop keeps this up to date as it processes `KeyDown`/`KeyUp`/
+/// `MouseButtonDown`/`MouseButtonUp`/`MouseMotion` events; `Context`
+/// exposes it through `is_key_pressed()`, `pressed_keys()`,
+/// `mouse_position()` and `is_mouse_button_pressed()`.
+pub struct InputState {
+    pressed_keys: HashSet<Keycode>,
+    modifiers: Mod,
+    mouse_position: (i32, i32),
+    pressed_mouse_buttons: HashSet<MouseButton>,
+}
+
+impl InputState {
+    pub fn new() -> Self {
+        Self {
+            pressed_keys: HashSet::new(),
+            modifiers: Mod::empty(),
+            mouse_position: (0, 0),
+            pressed_mouse_buttons: HashSet::new(),
+        }
+    }
+
+    fn handle_key_down(&mut self, keycode: Keycode, keymod: Mod) {
+        self.pressed_keys.insert(keycode);
+        self.modifiers = keymod;
+    }
+
+    fn handle_key_up(&mut self, keycode: Keycode, keymod: Mod) {
+        self.pressed_keys.remove(&keycode);
+        self.modifiers = keymod;
+    }
+
+    fn handle_mouse_button_down(&mut self, button: MouseButton) {
+        self.pressed_mouse_buttons.insert(button);
+    }
+
+    fn handle_mouse_button_up(&mut self, button: MouseButton) {
+        self.pressed_mouse_buttons.remove(&button);
+    }
+
+    fn handle_mouse_motion(&mut self, x: i32, y: i32) {
+        self.mouse_position = (x, y);
+    }
+
+    pub fn is_key_pressed(&self, keycode: Keycode) -> bool {
+        self.pressed_keys.contains(&keycode)
+    }
+
+    pub fn pressed_keys(&self) -> &HashSet<Keycode> {
+        &self.pressed_keys
+    }
+
+    pub fn modifiers(&self) -> Mod {
+        self.modifiers
+    }
+
+    pub fn mouse_position(&self) -> (i32, i32) {
+        self.mouse_position
+    }
+
+    pub fn is_mouse_button_pressed(&self, button: MouseButton) -> bool {
+        self.pressed_mouse_buttons.contains(&button)
+    }
+}
+
+impl Context {
+    /// Returns whether `keycode` is currently held down.
+    pub fn is_key_pressed(&self, keycode: Keycode) -> bool {
+        self.input_state.is_key_pressed(keycode)
+    }
+
+    /// Returns the set of keys currently held down.
+    pub fn pressed_keys(&self) -> &HashSet<Keycode> {
+        self.input_state.pressed_keys()
+    }
+
+    /// Returns the modifier (ctrl/shift/etc.) state as of the last key
+    /// event.
+    pub fn modifiers(&self) -> Mod {
+        self.input_state.modifiers()
+    }
+
+    /// Returns the mouse's last known position in window coordinates.
+    pub fn mouse_position(&self) -> (i32, i32) {
+        self.input_state.mouse_position()
+    }
+
+    /// Returns whether `button` is currently held down.
+    pub fn is_mouse_button_pressed(&self, button: MouseButton) -> bool {
+        self.input_state.is_mouse_button_pressed(button)
+    }
+
+    /// Marks `rect` as having changed since the last `draw()`. The `run()`
+    /// loop collects every rect invalidated this way each frame; when the
+    /// handler's `needs_redraw()` returns `false` it repaints only their
+    /// union instead of the whole screen. When `needs_redraw()` returns
+    /// `true` (the default) the collected rects are discarded and the
+    /// whole screen is redrawn.
+    pub fn invalidate(&mut self, rect: graphics::Rect) {
+        self.dirty_rects.push(rect);
+    }
+}
+
+/// Combines `rects` into the smallest `Rect` that contains all of them, or
+/// `None` if `rects` is empty.
+fn union_dirty_rects(rects: &[graphics::Rect]) -> Option<graphics::Rect> {
+    rects.iter().cloned().fold(None, |acc, rect| {
+        Some(match acc {
+            None => rect,
+            Some(acc) => {
+                let x = acc.x.min(rect.x);
+                let y = acc.y.min(rect.y);
+                let right = (acc.x + acc.w).max(rect.x + rect.w);
+                let bottom = (acc.y + acc.h).max(rect.y + rect.h);
+                graphics::Rect {
+                    x,
+                    y,
+                    w: right - x,
+                    h: bottom - y,
+                }
+            }
+        })
+    })
+}
+
 pub struct Assets {
     images: HashMap<String, graphics::Image>,
     font: HashMap<String, graphics::Font>,
@@ -66,13 +208,22 @@ impl Assets {
     }
 }
 
-pub enum Transition {
+pub enum Transition<E = GameError>
+    where E: StdError
+{
     None,
-    Push(Box<EventHandler>), // Pushes another state on the stack
-    Swap(Box<EventHandler>), // Removes current state from stack before adding the new one
+    Push(Box<EventHandler<E>>), // Pushes another state on the stack
+    Swap(Box<EventHandler<E>>), // Removes current state from stack before adding the new one
     Pop, // Remove state on top of stack
 }
 
+/// Where an error returned from `update` or `draw` originated, passed to
+/// `EventHandler::on_error` so a handler can react differently depending
+/// on which callback failed.
+pub enum ErrorOrigin {
+    Update,
+    Draw,
+}
 
 /// A trait defining event callbacks; your primary interface with
 /// `ggez`'s event loop.  Have a type implement this trait and
@@ -82,38 +233,96 @@ pub enum Transition {
 /// The default event handlers do nothing, apart from
 /// `key_down_event()`, which will by default exit the game if escape
 /// is pressed.  Just override the methods you want to do things with.
-pub trait EventHandler {
+///
+/// `EventHandler` is generic over the error type `E` returned by `update`
+/// and `draw`, defaulting to ggez's own `GameError`. This lets games use
+/// their own domain error type instead of shoehorning it into `GameError`;
+/// when `run()` gets an `Err` back from either callback it hands the error
+/// to `on_error` along with an `ErrorOrigin` instead of bubbling it up and
+/// killing the process.
+pub trait EventHandler<E = GameError>
+    where E: StdError
+{
     /// Called upon each physics update to the game.
     /// This should be where the game's logic takes place.
-    fn update(&mut self, ctx: &mut Context, assets: &Assets, dt: Duration) -> GameResult<Transition>;
+    fn update(&mut self, ctx: &mut Context, assets: &Assets, dt: Duration) -> Result<Transition<E>, E>;
 
     /// Called to do the drawing of your game.
     /// You probably want to start this with
     /// `graphics::clear()` and end it with
     /// `graphics::present()` and `timer::sleep_until_next_frame()`
-    fn draw(&mut self, ctx: &mut Context, assets: &Assets) -> GameResult<()>;
+    fn draw(&mut self, ctx: &mut Context, assets: &Assets) -> Result<(), E>;
+
+    /// Called when `update` or `draw` returns an error, instead of letting
+    /// the main loop bubble it up and kill the process. Return `true` to
+    /// keep running (after logging the error or showing an in-game error
+    /// screen, for example), or `false` to terminate, in which case the
+    /// error that triggered this call is propagated out of `run()`.
+    fn on_error(&mut self, _ctx: &mut Context, _origin: ErrorOrigin, _e: &E) -> bool {
+        false
+    }
 
-    fn mouse_button_down_event(&mut self, _button: mouse::MouseButton, _x: i32, _y: i32) {}
+    /// Returning `true` marks the event as handled, which keeps it from
+    /// reaching any handler layered beneath this one (see `EventLayers`).
+    fn mouse_button_down_event(&mut self, _button: mouse::MouseButton, _x: i32, _y: i32) -> bool {
+        false
+    }
 
-    fn mouse_button_up_event(&mut self, _button: mouse::MouseButton, _x: i32, _y: i32) {}
+    fn mouse_button_up_event(&mut self, _button: mouse::MouseButton, _x: i32, _y: i32) -> bool {
+        false
+    }
 
     fn mouse_motion_event(&mut self,
                           _state: mouse::MouseState,
                           _x: i32,
                           _y: i32,
                           _xrel: i32,
-                          _yrel: i32) {
+                          _yrel: i32) -> bool {
+        false
+    }
+
+    fn mouse_wheel_event(&mut self, _x: i32, _y: i32) -> bool {
+        false
     }
 
-    fn mouse_wheel_event(&mut self, _x: i32, _y: i32) {}
+    fn key_down_event(&mut self, _keycode: Keycode, _keymod: Mod, _repeat: bool) -> bool {
+        false
+    }
+
+    fn key_up_event(&mut self, _keycode: Keycode, _keymod: Mod, _repeat: bool) -> bool {
+        false
+    }
 
-    fn key_down_event(&mut self, _keycode: Keycode, _keymod: Mod, _repeat: bool) {}
+    fn controller_button_down_event(&mut self, _btn: Button, _instance_id: i32) -> bool {
+        false
+    }
+    fn controller_button_up_event(&mut self, _btn: Button, _instance_id: i32) -> bool {
+        false
+    }
+    fn controller_axis_event(&mut self, _axis: Axis, _value: i16, _instance_id: i32) -> bool {
+        false
+    }
 
-    fn key_up_event(&mut self, _keycode: Keycode, _keymod: Mod, _repeat: bool) {}
+    /// Hints whether `draw()` needs to run this frame. Defaults to `true`,
+    /// so games that don't opt into partial redraws keep repainting every
+    /// tick; return `false` once nothing has changed (and no region has
+    /// been reported via `ctx.invalidate()`) to let `run()` skip `draw()`
+    /// altogether.
+    fn needs_redraw(&self) -> bool {
+        true
+    }
 
-    fn controller_button_down_event(&mut self, _btn: Button, _instance_id: i32) {}
-    fn controller_button_up_event(&mut self, _btn: Button, _instance_id: i32) {}
-    fn controller_axis_event(&mut self, _axis: Axis, _value: i16, _instance_id: i32) {}
+    /// Called for touchscreen input: a finger going down, moving, lifting
+    /// off, or the gesture being cancelled. `x` and `y` are normalized to
+    /// the `0.0..=1.0` range as SDL2 reports them, and `id` identifies the
+    /// finger so multiple simultaneous touches can be tracked separately.
+    /// `run()` reports SDL2's `FingerDown`, `FingerMotion` and `FingerUp`
+    /// as `Started`, `Moved` and `Ended`; it never passes `Cancelled`.
+    /// Returning `true` marks the touch as handled, the same as the mouse
+    /// and keyboard callbacks above (see `EventLayers`).
+    fn touch_event(&mut self, _phase: TouchPhase, _x: f32, _y: f32, _id: i64) -> bool {
+        false
+    }
 
     fn focus_event(&mut self, _gained: bool) {}
 
@@ -125,13 +334,492 @@ pub trait EventHandler {
     }
 }
 
+/// A hook for wiring an immediate-mode debug overlay (sliders, toggles,
+/// live stats) into the event loop so every ggez game gets a built-in,
+/// toggleable developer console without reimplementing event forwarding
+/// itself.
+///
+/// The `run()` loop feeds every raw SDL event to `handle_event()` first,
+/// before the game's own `EventHandler` sees it; if the overlay reports
+/// the event was captured, because the mouse is hovering a widget, it is
+/// not forwarded to the game underneath. `build_ui()` runs once per
+/// `update()` so the overlay can lay itself out for the frame, and
+/// `render()` runs last in `draw()`, after the game has drawn, so the
+/// overlay always floats on top.
+pub trait DebugUi {
+    /// Feed a raw SDL event to the overlay. Return `true` if it was
+    /// captured and should be suppressed from reaching the game handler.
+    fn handle_event(&mut self, ctx: &mut Context, event: &event::Event) -> bool;
+
+    /// Build this frame's UI.
+    fn build_ui(&mut self, ctx: &mut Context, dt: Duration);
+
+    /// Render the overlay on top of whatever the game just drew.
+    fn render(&mut self, ctx: &mut Context);
+}
+
+/// A mutation to apply to an `EventLayers` stack, sent through its internal
+/// channel so a layer can add or remove layers -- including itself -- mid
+/// frame without needing mutable access to the stack.
+pub enum HandlerDiff<E = GameError>
+    where E: StdError
+{
+    /// Insert `inner` into the stack at z-order `id`.
+    Add { id: usize, inner: Box<EventHandler<E>> },
+    /// Remove the layer at z-order `id`.
+    Remove(usize),
+}
+
+/// Manages a stack of `EventHandler`s keyed by z-order, so a HUD, a pause
+/// menu, and the game world can all be active at once instead of forcing
+/// everything through a single `Transition`-based handler.
+///
+/// Layers are updated in z-order (lowest first) and drawn bottom-to-top so
+/// higher layers paint over lower ones. Input callbacks are dispatched
+/// top-to-bottom; as soon as one returns `true` the event is considered
+/// handled and is not forwarded to the layers beneath it, which lets a
+/// focused layer such as a modal dialog swallow input meant for it alone.
+pub struct EventLayers<E = GameError>
+    where E: StdError
+{
+    layers: BTreeMap<usize, Box<EventHandler<E>>>,
+    next_id: AtomicUsize,
+    sender: Sender<HandlerDiff<E>>,
+    receiver: Receiver<HandlerDiff<E>>,
+}
+
+impl<E> EventLayers<E>
+    where E: StdError
+{
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            layers: BTreeMap::new(),
+            next_id: AtomicUsize::new(0),
+            sender,
+            receiver,
+        }
+    }
+
+    /// Returns a clonable handle that a layer can hold onto and use to add
+    /// or remove layers mid-frame, without needing a mutable reference to
+    /// the `EventLayers` itself.
+    pub fn sender(&self) -> Sender<HandlerDiff<E>> {
+        self.sender.clone()
+    }
+
+    /// Reserves the next z-order id without inserting anything, so a
+    /// caller that only has a `Sender<HandlerDiff>` can still build an
+    /// `Add` diff ahead of time.
+    pub fn reserve_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Inserts `inner` at the next available z-order and returns the id it
+    /// was assigned.
+    pub fn add(&mut self, inner: Box<EventHandler<E>>) -> usize {
+        let id = self.reserve_id();
+        self.layers.insert(id, inner);
+        id
+    }
+
+    /// Removes the layer at z-order `id`, if one exists.
+    pub fn remove(&mut self, id: usize) {
+        self.layers.remove(&id);
+    }
+
+    fn apply_diffs(&mut self) {
+        while let Ok(diff) = self.receiver.try_recv() {
+            match diff {
+                HandlerDiff::Add { id, inner } => {
+                    self.layers.insert(id, inner);
+                }
+                HandlerDiff::Remove(id) => {
+                    self.layers.remove(&id);
+                }
+            }
+        }
+    }
+}
+
+impl<E> EventHandler<E> for EventLayers<E>
+    where E: StdError
+{
+    fn update(&mut self,
+              ctx: &mut Context,
+              assets: &Assets,
+              dt: Duration)
+              -> Result<Transition<E>, E> {
+        self.apply_diffs();
+
+        for layer in self.layers.values_mut() {
+            if let Err(e) = layer.update(ctx, assets, dt) {
+                if !layer.on_error(ctx, ErrorOrigin::Update, &e) {
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(Transition::None)
+    }
+
+    fn draw(&mut self, ctx: &mut Context, assets: &Assets) -> Result<(), E> {
+        for layer in self.layers.values_mut() {
+            if let Err(e) = layer.draw(ctx, assets) {
+                if !layer.on_error(ctx, ErrorOrigin::Draw, &e) {
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn mouse_button_down_event(&mut self, button: mouse::MouseButton, x: i32, y: i32) -> bool {
+        for layer in self.layers.values_mut().rev() {
+            if layer.mouse_button_down_event(button, x, y) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn mouse_button_up_event(&mut self, button: mouse::MouseButton, x: i32, y: i32) -> bool {
+        for layer in self.layers.values_mut().rev() {
+            if layer.mouse_button_up_event(button, x, y) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn mouse_motion_event(&mut self,
+                          state: mouse::MouseState,
+                          x: i32,
+                          y: i32,
+                          xrel: i32,
+                          yrel: i32) -> bool {
+        for layer in self.layers.values_mut().rev() {
+            if layer.mouse_motion_event(state, x, y, xrel, yrel) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn mouse_wheel_event(&mut self, x: i32, y: i32) -> bool {
+        for layer in self.layers.values_mut().rev() {
+            if layer.mouse_wheel_event(x, y) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn key_down_event(&mut self, keycode: Keycode, keymod: Mod, repeat: bool) -> bool {
+        for layer in self.layers.values_mut().rev() {
+            if layer.key_down_event(keycode, keymod, repeat) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn key_up_event(&mut self, keycode: Keycode, keymod: Mod, repeat: bool) -> bool {
+        for layer in self.layers.values_mut().rev() {
+            if layer.key_up_event(keycode, keymod, repeat) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn controller_button_down_event(&mut self, btn: Button, instance_id: i32) -> bool {
+        for layer in self.layers.values_mut().rev() {
+            if layer.controller_button_down_event(btn, instance_id) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn controller_button_up_event(&mut self, btn: Button, instance_id: i32) -> bool {
+        for layer in self.layers.values_mut().rev() {
+            if layer.controller_button_up_event(btn, instance_id) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn controller_axis_event(&mut self, axis: Axis, value: i16, instance_id: i32) -> bool {
+        for layer in self.layers.values_mut().rev() {
+            if layer.controller_axis_event(axis, value, instance_id) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn needs_redraw(&self) -> bool {
+        // Any invalidated regions a layer reports go straight through the
+        // shared `Context` via `ctx.invalidate()`, so all this needs to do
+        // is OR together the layers' hints about whether they have
+        // anything new to draw at all.
+        self.layers.values().any(|layer| layer.needs_redraw())
+    }
+
+    fn touch_event(&mut self, phase: TouchPhase, x: f32, y: f32, id: i64) -> bool {
+        for layer in self.layers.values_mut().rev() {
+            if layer.touch_event(phase, x, y, id) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn focus_event(&mut self, gained: bool) {
+        for layer in self.layers.values_mut() {
+            layer.focus_event(gained);
+        }
+    }
+
+    fn quit_event(&mut self) -> bool {
+        // Keep running if *any* layer wants to; only quit once every layer
+        // has agreed to let the game exit.
+        let mut keep_running = false;
+        for layer in self.layers.values_mut().rev() {
+            if layer.quit_event() {
+                keep_running = true;
+            }
+        }
+        keep_running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::fmt;
+    use std::rc::Rc;
+
+    #[derive(Debug)]
+    struct TestError;
+
+    impl fmt::Display for TestError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "test error")
+        }
+    }
+
+    impl StdError for TestError {
+        fn description(&self) -> &str {
+            "test error"
+        }
+    }
+
+    // A stand-in layer that records its id into a shared call log when an
+    // input callback reaches it, and reports the event handled (or not) and
+    // whether it wants a redraw, as configured by the test. `update`/`draw`
+    // are never exercised here -- they take a live `Context`, which needs
+    // SDL2 and isn't constructible in a unit test.
+    struct DummyHandler {
+        id: i32,
+        calls: Rc<RefCell<Vec<i32>>>,
+        handled: bool,
+        redraw: bool,
+    }
+
+    impl DummyHandler {
+        fn new(id: i32, calls: Rc<RefCell<Vec<i32>>>, handled: bool) -> Self {
+            DummyHandler {
+                id,
+                calls,
+                handled,
+                redraw: false,
+            }
+        }
+    }
+
+    impl EventHandler<TestError> for DummyHandler {
+        fn update(&mut self,
+                  _ctx: &mut Context,
+                  _assets: &Assets,
+                  _dt: Duration)
+                  -> Result<Transition<TestError>, TestError> {
+            Ok(Transition::None)
+        }
+
+        fn draw(&mut self, _ctx: &mut Context, _assets: &Assets) -> Result<(), TestError> {
+            Ok(())
+        }
+
+        fn mouse_button_down_event(&mut self, _button: MouseButton, _x: i32, _y: i32) -> bool {
+            self.calls.borrow_mut().push(self.id);
+            self.handled
+        }
+
+        fn touch_event(&mut self, _phase: TouchPhase, _x: f32, _y: f32, _id: i64) -> bool {
+            self.calls.borrow_mut().push(self.id);
+            self.handled
+        }
+
+        fn needs_redraw(&self) -> bool {
+            self.redraw
+        }
+    }
+
+    #[test]
+    fn union_dirty_rects_of_no_rects_is_none() {
+        assert!(union_dirty_rects(&[]).is_none());
+    }
+
+    #[test]
+    fn union_dirty_rects_of_one_rect_is_that_rect() {
+        let rect = graphics::Rect {
+            x: 10.0,
+            y: 20.0,
+            w: 30.0,
+            h: 40.0,
+        };
+
+        let union = union_dirty_rects(&[rect]).unwrap();
+        assert_eq!(union.x, rect.x);
+        assert_eq!(union.y, rect.y);
+        assert_eq!(union.w, rect.w);
+        assert_eq!(union.h, rect.h);
+    }
+
+    #[test]
+    fn union_dirty_rects_covers_every_rect() {
+        let a = graphics::Rect { x: 0.0, y: 0.0, w: 10.0, h: 10.0 };
+        let b = graphics::Rect { x: 20.0, y: 5.0, w: 5.0, h: 5.0 };
+
+        let union = union_dirty_rects(&[a, b]).unwrap();
+        assert_eq!(union.x, 0.0);
+        assert_eq!(union.y, 0.0);
+        assert_eq!(union.w, 25.0);
+        assert_eq!(union.h, 10.0);
+    }
+
+    #[test]
+    fn reserve_id_hands_out_increasing_ids() {
+        let layers: EventLayers<TestError> = EventLayers::new();
+        assert_eq!(layers.reserve_id(), 0);
+        assert_eq!(layers.reserve_id(), 1);
+        assert_eq!(layers.reserve_id(), 2);
+    }
+
+    #[test]
+    fn add_and_remove_change_the_layer_count() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut layers: EventLayers<TestError> = EventLayers::new();
+        let id = layers.add(Box::new(DummyHandler::new(0, calls, false)));
+        assert_eq!(layers.layers.len(), 1);
+
+        layers.remove(id);
+        assert!(layers.layers.is_empty());
+    }
+
+    #[test]
+    fn apply_diffs_adds_and_removes_via_the_channel() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut layers: EventLayers<TestError> = EventLayers::new();
+        let sender = layers.sender();
+        let id = layers.reserve_id();
+
+        sender.send(HandlerDiff::Add {
+                id,
+                inner: Box::new(DummyHandler::new(0, calls, false)),
+            })
+            .unwrap();
+        layers.apply_diffs();
+        assert_eq!(layers.layers.len(), 1);
+
+        sender.send(HandlerDiff::Remove(id)).unwrap();
+        layers.apply_diffs();
+        assert!(layers.layers.is_empty());
+    }
+
+    #[test]
+    fn input_dispatch_goes_top_to_bottom_and_stops_once_handled() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut layers: EventLayers<TestError> = EventLayers::new();
+        layers.add(Box::new(DummyHandler::new(0, calls.clone(), false)));
+        layers.add(Box::new(DummyHandler::new(1, calls.clone(), true)));
+        layers.add(Box::new(DummyHandler::new(2, calls.clone(), false)));
+
+        let handled = layers.mouse_button_down_event(MouseButton::Left, 0, 0);
+
+        assert!(handled);
+        // Layer 2 is topmost (highest z-order) and is checked first; layer
+        // 1 swallows the event, so layer 0 underneath is never reached.
+        assert_eq!(*calls.borrow(), vec![2, 1]);
+    }
+
+    #[test]
+    fn touch_event_dispatch_also_stops_once_handled() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut layers: EventLayers<TestError> = EventLayers::new();
+        layers.add(Box::new(DummyHandler::new(0, calls.clone(), true)));
+        layers.add(Box::new(DummyHandler::new(1, calls.clone(), false)));
+
+        let handled = layers.touch_event(TouchPhase::Started, 0.5, 0.5, 0);
+
+        assert!(handled);
+        assert_eq!(*calls.borrow(), vec![1, 0]);
+    }
+
+    #[test]
+    fn input_dispatch_reaches_every_layer_when_none_handle_it() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut layers: EventLayers<TestError> = EventLayers::new();
+        layers.add(Box::new(DummyHandler::new(0, calls.clone(), false)));
+        layers.add(Box::new(DummyHandler::new(1, calls.clone(), false)));
+
+        let handled = layers.mouse_button_down_event(MouseButton::Left, 0, 0);
+
+        assert!(!handled);
+        assert_eq!(*calls.borrow(), vec![1, 0]);
+    }
+
+    #[test]
+    fn needs_redraw_is_true_if_any_layer_wants_a_redraw() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut layers: EventLayers<TestError> = EventLayers::new();
+        let mut quiet = DummyHandler::new(0, calls.clone(), false);
+        quiet.redraw = false;
+        let mut loud = DummyHandler::new(1, calls, false);
+        loud.redraw = true;
+        layers.add(Box::new(quiet));
+        layers.add(Box::new(loud));
+
+        assert!(layers.needs_redraw());
+    }
+
+    #[test]
+    fn needs_redraw_is_false_if_no_layer_wants_a_redraw() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut layers: EventLayers<TestError> = EventLayers::new();
+        layers.add(Box::new(DummyHandler::new(0, calls, false)));
+
+        assert!(!layers.needs_redraw());
+    }
+}
+
 /*
 /// Runs the game's main loop, calling event callbacks on the given state
 /// object as events occur.
 ///
 /// It does not try to do any type of framerate limiting.  See the
 /// documentation for the `timer` module for more info.
-pub fn run<S, T>(ctx: &mut Context, state: &mut S) -> GameResult<()>
+///
+/// `ui`, if present, sees every raw SDL event before `state` does and can
+/// capture it (suppressing it from reaching `state`); it then gets a
+/// per-frame build step alongside `update()` and renders last, after
+/// `state.draw()`, so a debug overlay always floats on top of the game.
+pub fn run<S, T>(ctx: &mut Context, state: &mut S, mut ui: Option<&mut DebugUi>) -> GameResult<()>
     where S: EventHandler
 {
     {
@@ -142,6 +830,12 @@ pub fn run<S, T>(ctx: &mut Context, state: &mut S) -> GameResult<()>
             ctx.timer_context.tick();
 
             for event in event_pump.poll_iter() {
+                if let Some(ref mut ui) = ui {
+                    if ui.handle_event(ctx, &event) {
+                        continue;
+                    }
+                }
+
                 match event {
                     Quit { .. } => {
                         continuing = state.quit_event();
@@ -154,6 +848,7 @@ pub fn run<S, T>(ctx: &mut Context, state: &mut S) -> GameResult<()>
                         ..
                     } => {
                         if let Some(key) = keycode {
+                            ctx.input_state.handle_key_down(key, keymod);
                             if key == keyboard::Keycode::Escape {
                                 ctx.quit()?;
                             } else {
@@ -168,13 +863,16 @@ pub fn run<S, T>(ctx: &mut Context, state: &mut S) -> GameResult<()>
                         ..
                     } => {
                         if let Some(key) = keycode {
+                            ctx.input_state.handle_key_up(key, keymod);
                             state.key_up_event(key, keymod, repeat)
                         }
                     }
                     MouseButtonDown { mouse_btn, x, y, .. } => {
+                        ctx.input_state.handle_mouse_button_down(mouse_btn);
                         state.mouse_button_down_event(mouse_btn, x, y)
                     }
                     MouseButtonUp { mouse_btn, x, y, .. } => {
+                        ctx.input_state.handle_mouse_button_up(mouse_btn);
                         state.mouse_button_up_event(mouse_btn, x, y)
                     }
                     MouseMotion {
@@ -184,7 +882,10 @@ pub fn run<S, T>(ctx: &mut Context, state: &mut S) -> GameResult<()>
                         xrel,
                         yrel,
                         ..
-                    } => state.mouse_motion_event(mousestate, x, y, xrel, yrel),
+                    } => {
+                        ctx.input_state.handle_mouse_motion(x, y);
+                        state.mouse_motion_event(mousestate, x, y, xrel, yrel)
+                    }
                     MouseWheel { x, y, .. } => state.mouse_wheel_event(x, y),
                     ControllerButtonDown { button, which, .. } => {
                         state.controller_button_down_event(button, which)
@@ -200,13 +901,52 @@ pub fn run<S, T>(ctx: &mut Context, state: &mut S) -> GameResult<()>
                     Window { win_event: event::WindowEvent::FocusLost, .. } => {
                         state.focus_event(false)
                     }
+                    FingerDown { finger_id, x, y, .. } => {
+                        state.touch_event(TouchPhase::Started, x, y, finger_id)
+                    }
+                    FingerMotion { finger_id, x, y, .. } => {
+                        state.touch_event(TouchPhase::Moved, x, y, finger_id)
+                    }
+                    FingerUp { finger_id, x, y, .. } => {
+                        state.touch_event(TouchPhase::Ended, x, y, finger_id)
+                    }
                     _ => {}
                 }
             }
 
             let dt = timer::get_delta(ctx);
-            state.update(ctx, dt)?;
-            state.draw(ctx)?;
+            if let Some(ref mut ui) = ui {
+                ui.build_ui(ctx, dt);
+            }
+            if let Err(e) = state.update(ctx, dt) {
+                if !state.on_error(ctx, ErrorOrigin::Update, &e) {
+                    return Err(e);
+                }
+            }
+
+            let dirty_rects = ctx.dirty_rects.drain(..).collect::<Vec<_>>();
+            if state.needs_redraw() {
+                // A full repaint was requested, so ignore any dirty rects
+                // -- scissoring to them here would wrongly clip a frame
+                // that wants the whole screen redrawn.
+                if let Err(e) = state.draw(ctx) {
+                    if !state.on_error(ctx, ErrorOrigin::Draw, &e) {
+                        return Err(e);
+                    }
+                }
+            } else if let Some(scissor) = union_dirty_rects(&dirty_rects) {
+                graphics::set_scissor_rect(ctx, scissor)?;
+                if let Err(e) = state.draw(ctx) {
+                    if !state.on_error(ctx, ErrorOrigin::Draw, &e) {
+                        return Err(e);
+                    }
+                }
+                graphics::clear_scissor_rect(ctx)?;
+            }
+
+            if let Some(ref mut ui) = ui {
+                ui.render(ctx);
+            }
         }
     }
 